@@ -0,0 +1,455 @@
+// ================================================================================================
+// File: assets.rs
+// Author: Guilherme R. Lampert
+// Created on: 16/10/26
+// Brief: Asset path resolution and packaged asset bundles.
+//
+// This source code is released under the MIT license.
+// See the accompanying LICENSE file for details.
+// ================================================================================================
+
+use std;
+use std::path::{Path, PathBuf};
+use std::fs::File;
+use std::io::Read;
+
+use citysim::common::*;
+
+// ----------------------------------------------
+// AssetBundle
+// ----------------------------------------------
+
+// Packed asset archive used by release builds. Bundles are plain
+// (uncompressed) tar files. The whole archive is loaded into memory
+// and an index of its entries is built once when it is opened.
+pub struct AssetBundle {
+    file_path: PathBuf,
+    data:      Vec<u8>,
+    entries:   Vec<AssetBundleEntry>, // Sorted by name for faster binary searches.
+}
+
+struct AssetBundleEntry {
+    name:   String, // Asset path inside the bundle, always with '/' separators.
+    offset: usize,  // Start of the file data in the archive.
+    size:   usize,  // File size in bytes.
+}
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+impl AssetBundle {
+    pub fn open(file_path: &Path) -> Option<AssetBundle> {
+        let mut data = Vec::new();
+        match File::open(file_path) {
+            Err(_)       => return None,
+            Ok(mut file) => if file.read_to_end(&mut data).is_err() { return None; },
+        }
+
+        let entries = match AssetBundle::build_index(&data) {
+            Some(entries) => entries,
+            None          => {
+                println!("Asset bundle \"{}\" is malformed!", file_path.display());
+                return None;
+            }
+        };
+
+        println!("Asset bundle \"{}\" opened ({} entries).", file_path.display(), entries.len());
+        Some(AssetBundle{ file_path: file_path.to_path_buf(), data: data, entries: entries })
+    }
+
+    pub fn get_file_path(&self) -> &Path {
+        &self.file_path
+    }
+
+    pub fn get_entry_count(&self) -> i32 {
+        self.entries.len() as i32
+    }
+
+    pub fn contains(&self, asset_name: &str) -> bool {
+        self.find_entry(asset_name).is_some()
+    }
+
+    pub fn read(&self, asset_name: &str) -> Option<&[u8]> {
+        match self.find_entry(asset_name) {
+            Some(entry) => Some(&self.data[entry.offset .. entry.offset + entry.size]),
+            None        => None,
+        }
+    }
+
+    fn find_entry(&self, asset_name: &str) -> Option<&AssetBundleEntry> {
+        match self.entries.binary_search_by(|probe| probe.name.as_str().cmp(asset_name)) {
+            Err(_)    => None,
+            Ok(index) => Some(&self.entries[index]),
+        }
+    }
+
+    fn build_index(data: &[u8]) -> Option<Vec<AssetBundleEntry>> {
+        let mut entries = Vec::new();
+        let mut offset  = 0;
+
+        // Set by a GNU long name ('L') or pax ('x') header and applied to the entry that follows it.
+        let mut long_name: Option<String> = None;
+
+        while offset + TAR_BLOCK_SIZE <= data.len() {
+            let header = &data[offset .. offset + TAR_BLOCK_SIZE];
+
+            // Two all-zero blocks mark the end of the archive.
+            if header.iter().all(|b| *b == 0) {
+                break;
+            }
+
+            let size = match AssetBundle::parse_octal(&header[124 .. 136]) {
+                Some(size) => size,
+                None       => return None,
+            };
+
+            let data_offset = offset + TAR_BLOCK_SIZE;
+            if data_offset + size > data.len() {
+                return None;
+            }
+            let file_data = &data[data_offset .. data_offset + size];
+
+            match header[156] {
+                // Regular file:
+                b'0' | 0 => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None       => AssetBundle::parse_header_name(header),
+                    };
+                    entries.push(AssetBundleEntry{
+                        name:   normalize_asset_name(&name),
+                        offset: data_offset,
+                        size:   size,
+                    });
+                }
+                // GNU long name for the next entry:
+                b'L' => {
+                    long_name = Some(AssetBundle::parse_string(file_data));
+                }
+                // pax extended header for the next entry. Only the path is relevant to us.
+                b'x' => {
+                    match AssetBundle::parse_pax_path(file_data) {
+                        Err(_)   => return None,
+                        Ok(path) => if path.is_some() { long_name = path; },
+                    }
+                }
+                // Global pax headers don't name anything.
+                b'g' => {}
+                // Directories, links, etc are not indexed, but still consume any pending long name.
+                _ => {
+                    long_name = None;
+                }
+            }
+
+            let padded_size = (size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+            offset = data_offset + padded_size;
+        }
+
+        // A long name header with no entry after it means the archive was cut short.
+        if long_name.is_some() {
+            return None;
+        }
+
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Some(entries)
+    }
+
+    fn parse_header_name(header: &[u8]) -> String {
+        let name = AssetBundle::parse_string(&header[0 .. 100]);
+
+        // The ustar format splits long paths into a prefix and a name.
+        if &header[257 .. 262] == b"ustar" {
+            let prefix = AssetBundle::parse_string(&header[345 .. 500]);
+            if !prefix.is_empty() {
+                return format!("{}/{}", prefix, name);
+            }
+        }
+        name
+    }
+
+    // pax records are "<length> <key>=<value>\n", where length counts the whole record.
+    fn parse_pax_path(records: &[u8]) -> Result<Option<String>, ()> {
+        let mut path = None;
+        let mut pos  = 0;
+
+        while pos < records.len() {
+            // Some writers pad the data with NULs.
+            if records[pos] == 0 {
+                break;
+            }
+
+            let space = match records[pos ..].iter().position(|b| *b == b' ') {
+                Some(index) => pos + index,
+                None        => return Err(()),
+            };
+            let length = match String::from_utf8_lossy(&records[pos .. space]).parse::<usize>() {
+                Ok(length) => length,
+                Err(_)     => return Err(()),
+            };
+            if length == 0 || pos + length > records.len() || records[pos + length - 1] != b'\n' {
+                return Err(());
+            }
+
+            let record = String::from_utf8_lossy(&records[space + 1 .. pos + length - 1]).into_owned();
+            if record.starts_with("path=") {
+                path = Some(record[5 ..].to_string());
+            }
+            pos += length;
+        }
+
+        Ok(path)
+    }
+
+    fn parse_string(field: &[u8]) -> String {
+        let len = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+        String::from_utf8_lossy(&field[.. len]).into_owned()
+    }
+
+    fn parse_octal(field: &[u8]) -> Option<usize> {
+        let text = AssetBundle::parse_string(field);
+        let text = text.trim();
+        if text.is_empty() {
+            return Some(0);
+        }
+        usize::from_str_radix(text, 8).ok()
+    }
+}
+
+// ----------------------------------------------
+// AssetLoader
+// ----------------------------------------------
+
+// Locates the game assets independently of the current working directory.
+// Assets are referenced by '/' separated names relative to the asset root
+// (e.g. "atlases/house-tileset.png"). When an asset bundle is found next to
+// the assets it takes precedence over loose files on disk.
+pub struct AssetLoader {
+    root_dir: PathBuf,
+    bundle:   Option<AssetBundle>,
+}
+
+impl AssetLoader {
+    pub fn new() -> AssetLoader {
+        let root_dir = AssetLoader::find_root_dir();
+        let bundle   = AssetBundle::open(&root_dir.join(ASSET_BUNDLE_FILE_NAME));
+        println!("Asset root directory is \"{}\".", root_dir.display());
+        AssetLoader{ root_dir: root_dir, bundle: bundle }
+    }
+
+    pub fn get_root_dir(&self) -> &Path {
+        &self.root_dir
+    }
+
+    pub fn get_bundle(&self) -> Option<&AssetBundle> {
+        self.bundle.as_ref()
+    }
+
+    // Full file system path for a loose asset file.
+    pub fn resolve_path(&self, asset_name: &str) -> PathBuf {
+        let mut path = self.root_dir.clone();
+        for component in asset_name.split('/').filter(|c| !c.is_empty()) {
+            path.push(component);
+        }
+        path
+    }
+
+    pub fn read_bytes(&self, asset_name: &str) -> Option<Vec<u8>> {
+        let asset_name = normalize_asset_name(asset_name);
+
+        if let Some(ref bundle) = self.bundle {
+            if let Some(bytes) = bundle.read(&asset_name) {
+                return Some(bytes.to_vec());
+            }
+        }
+
+        let mut bytes = Vec::new();
+        match File::open(self.resolve_path(&asset_name)) {
+            Err(_)       => None,
+            Ok(mut file) => if file.read_to_end(&mut bytes).is_ok() { Some(bytes) } else { None },
+        }
+    }
+
+    // Try the executable's directory and a few of its parents first (covers
+    // running from cargo's target directory), then fall back to the cwd.
+    fn find_root_dir() -> PathBuf {
+        if let Ok(exe_path) = std::env::current_exe() {
+            let mut dir = exe_path.parent().map(|p| p.to_path_buf());
+            let mut depth = 0;
+            while let Some(candidate) = dir {
+                if depth > ASSET_ROOT_MAX_SEARCH_DEPTH {
+                    break;
+                }
+                if AssetLoader::is_asset_root(&candidate) {
+                    return candidate;
+                }
+                dir = candidate.parent().map(|p| p.to_path_buf());
+                depth += 1;
+            }
+        }
+        std::env::current_dir().unwrap_or(PathBuf::from("."))
+    }
+
+    fn is_asset_root(dir: &Path) -> bool {
+        dir.join(ASSET_BUNDLE_FILE_NAME).is_file() || dir.join(TEXTURE_ATLAS_BASE_PATH).is_dir()
+    }
+}
+
+// Asset names always use '/' separators and never start with "./".
+fn normalize_asset_name(name: &str) -> String {
+    let name = name.replace('\\', "/");
+    let mut trimmed = name.as_str();
+    while trimmed.starts_with("./") {
+        trimmed = &trimmed[2 ..];
+    }
+    while trimmed.starts_with('/') {
+        trimmed = &trimmed[1 ..];
+    }
+    trimmed.to_string()
+}
+
+// ----------------------------------------------
+// Unit tests
+// ----------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use super::{AssetBundle, normalize_asset_name, TAR_BLOCK_SIZE};
+
+    fn tar_header(name: &str, size: usize, type_flag: u8) -> Vec<u8> {
+        let mut header = vec![0; TAR_BLOCK_SIZE];
+        header[.. name.len()].copy_from_slice(name.as_bytes());
+        let size_field = format!("{:011o}", size);
+        header[124 .. 135].copy_from_slice(size_field.as_bytes());
+        header[156] = type_flag;
+        header
+    }
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, type_flag: u8, contents: &[u8]) {
+        archive.extend_from_slice(&tar_header(name, contents.len(), type_flag));
+        archive.extend_from_slice(contents);
+        let padding = (TAR_BLOCK_SIZE - contents.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        archive.extend(vec![0; padding]);
+    }
+
+    fn tar_end(archive: &mut Vec<u8>) {
+        archive.extend(vec![0; TAR_BLOCK_SIZE * 2]);
+    }
+
+    fn pax_record(key: &str, value: &str) -> String {
+        // The length prefix counts itself, so grow it until it is stable.
+        let body = format!(" {}={}\n", key, value);
+        let mut length = body.len() + 1;
+        while format!("{}{}", length, body).len() != length {
+            length += 1;
+        }
+        format!("{}{}", length, body)
+    }
+
+    fn open_bundle(archive: Vec<u8>) -> Option<AssetBundle> {
+        match AssetBundle::build_index(&archive) {
+            Some(entries) => Some(AssetBundle{ file_path: Default::default(), data: archive, entries: entries }),
+            None          => None,
+        }
+    }
+
+    #[test]
+    fn parse_octal_values() {
+        assert_eq!(AssetBundle::parse_octal(b"00000000644\0"), Some(0o644));
+        assert_eq!(AssetBundle::parse_octal(b"  17 \0\0"), Some(0o17));
+        assert_eq!(AssetBundle::parse_octal(b"\0\0\0\0"), Some(0));
+        assert_eq!(AssetBundle::parse_octal(b"0000089\0"), None);
+    }
+
+    #[test]
+    fn normalize_names() {
+        assert_eq!(normalize_asset_name("./atlases/a.png"), "atlases/a.png");
+        assert_eq!(normalize_asset_name("././a"), "a");
+        assert_eq!(normalize_asset_name("/atlases/a.png"), "atlases/a.png");
+        assert_eq!(normalize_asset_name("atlases\\a.png"), "atlases/a.png");
+        assert_eq!(normalize_asset_name("atlases/a.png"), "atlases/a.png");
+    }
+
+    #[test]
+    fn index_regular_files() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "./zeta.xml", b'0', b"<xml/>");
+        tar_entry(&mut archive, "atlases/", b'5', b"");
+        tar_entry(&mut archive, "atlases/alpha.png", 0, &[7; 600]);
+        tar_end(&mut archive);
+
+        let bundle = open_bundle(archive).unwrap();
+        assert_eq!(bundle.get_entry_count(), 2);
+        assert_eq!(bundle.entries[0].name, "atlases/alpha.png");
+        assert_eq!(bundle.entries[1].name, "zeta.xml");
+        assert_eq!(bundle.read("zeta.xml").unwrap(), b"<xml/>");
+        assert_eq!(bundle.read("atlases/alpha.png").unwrap(), &[7; 600][..]);
+        assert!(!bundle.contains("atlases"));
+    }
+
+    #[test]
+    fn index_ustar_prefix() {
+        let mut header = tar_header("file.png", 0, b'0');
+        header[257 .. 262].copy_from_slice(b"ustar");
+        header[345 .. 353].copy_from_slice(b"some/dir");
+        let mut archive = header;
+        tar_end(&mut archive);
+
+        let bundle = open_bundle(archive).unwrap();
+        assert!(bundle.contains("some/dir/file.png"));
+    }
+
+    #[test]
+    fn index_gnu_long_name() {
+        let long_name = format!("atlases/{}.png", "x".repeat(120));
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "././@LongLink", b'L', format!("{}\0", long_name).as_bytes());
+        tar_entry(&mut archive, &long_name[.. 100], b'0', b"data");
+        tar_end(&mut archive);
+
+        let bundle = open_bundle(archive).unwrap();
+        assert_eq!(bundle.get_entry_count(), 1);
+        assert_eq!(bundle.read(&long_name).unwrap(), b"data");
+    }
+
+    #[test]
+    fn index_pax_path() {
+        let long_name = format!("atlases/{}.xml", "y".repeat(120));
+        let records = format!("{}{}", pax_record("mtime", "1234.5"), pax_record("path", &long_name));
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "PaxHeaders/1", b'g', pax_record("comment", "global").as_bytes());
+        tar_entry(&mut archive, "PaxHeaders/2", b'x', records.as_bytes());
+        tar_entry(&mut archive, "truncated", b'0', b"pax");
+        tar_entry(&mut archive, "short.txt", b'0', b"plain");
+        tar_end(&mut archive);
+
+        let bundle = open_bundle(archive).unwrap();
+        assert_eq!(bundle.read(&long_name).unwrap(), b"pax");
+        assert_eq!(bundle.read("short.txt").unwrap(), b"plain");
+        assert!(!bundle.contains("truncated"));
+    }
+
+    #[test]
+    fn reject_malformed_archives() {
+        // Data runs past the end of the archive:
+        let mut archive = tar_header("file", 1000, b'0');
+        archive.extend(vec![0; 10]);
+        assert!(open_bundle(archive).is_none());
+
+        // Bad size field:
+        let mut archive = tar_header("file", 0, b'0');
+        archive[124 .. 135].copy_from_slice(b"zzzzzzzzzzz");
+        assert!(open_bundle(archive).is_none());
+
+        // Bad pax record:
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "PaxHeaders/1", b'x', b"99 path=oops\n");
+        tar_entry(&mut archive, "file", b'0', b"");
+        tar_end(&mut archive);
+        assert!(open_bundle(archive).is_none());
+
+        // Long name with nothing after it:
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "././@LongLink", b'L', b"dangling\0");
+        tar_end(&mut archive);
+        assert!(open_bundle(archive).is_none());
+    }
+}
@@ -0,0 +1,98 @@
+// ================================================================================================
+// File: clock.rs
+// Author: Guilherme R. Lampert
+// Created on: 16/10/26
+// Brief: Frame timing utilities.
+//
+// This source code is released under the MIT license.
+// See the accompanying LICENSE file for details.
+// ================================================================================================
+
+use std::time::{Duration, Instant};
+use citysim::common::*;
+
+// ----------------------------------------------
+// FrameClock
+// ----------------------------------------------
+
+// Measures the time between frames. Two time lines are kept:
+//  - Real time: plain wall clock time, unaffected by any scaling.
+//  - Game time: frame delta clamped to a maximum (so a long stall after a
+//    breakpoint or window drag doesn't produce a huge simulation step)
+//    and then multiplied by the time scale (for slow motion or speed up).
+pub struct FrameClock {
+    last_frame_time:   Instant,
+    delta_real_secs:   f32,
+    delta_game_secs:   f32,
+    elapsed_real_secs: f64,
+    elapsed_game_secs: f64,
+    max_delta_secs:    f32,
+    time_scale:        f32,
+    frame_count:       u64,
+}
+
+impl FrameClock {
+    pub fn new() -> FrameClock {
+        FrameClock{
+            last_frame_time:   Instant::now(),
+            delta_real_secs:   0.0,
+            delta_game_secs:   0.0,
+            elapsed_real_secs: 0.0,
+            elapsed_game_secs: 0.0,
+            max_delta_secs:    FRAME_CLOCK_MAX_DELTA_SECS,
+            time_scale:        1.0,
+            frame_count:       0,
+        }
+    }
+
+    // Call once at the start of every frame.
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        let delta = FrameClock::duration_to_secs(now.duration_since(self.last_frame_time));
+        self.last_frame_time = now;
+
+        self.delta_real_secs = delta as f32;
+        self.delta_game_secs = self.delta_real_secs.min(self.max_delta_secs) * self.time_scale;
+
+        self.elapsed_real_secs += delta;
+        self.elapsed_game_secs += self.delta_game_secs as f64;
+        self.frame_count += 1;
+    }
+
+    // Clamped and scaled frame delta. This is what game systems should use.
+    pub fn get_delta_time(&self) -> f32 {
+        self.delta_game_secs
+    }
+    pub fn get_real_delta_time(&self) -> f32 {
+        self.delta_real_secs
+    }
+
+    pub fn get_elapsed_game_time(&self) -> f64 {
+        self.elapsed_game_secs
+    }
+    pub fn get_elapsed_real_time(&self) -> f64 {
+        self.elapsed_real_secs
+    }
+
+    pub fn get_frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn get_time_scale(&self) -> f32 {
+        self.time_scale
+    }
+    pub fn set_time_scale(&mut self, scale: f32) {
+        self.time_scale = scale.max(0.0);
+    }
+
+    pub fn get_max_delta_time(&self) -> f32 {
+        self.max_delta_secs
+    }
+    pub fn set_max_delta_time(&mut self, max_secs: f32) {
+        self.max_delta_secs = max_secs.max(0.0);
+    }
+
+    fn duration_to_secs(duration: Duration) -> f64 {
+        (duration.as_secs() as f64) + (duration.subsec_nanos() as f64) * 1e-9
+    }
+}
@@ -9,8 +9,6 @@
 // See the accompanying LICENSE file for details.
 // ================================================================================================
 
-use std;
-
 // ----------------------------------------------
 // Color
 // ----------------------------------------------
@@ -30,6 +28,25 @@ impl Color {
     pub fn red()   -> Color { Color{ r: 1.0, g: 0.0, b: 0.0, a: 1.0 } }
     pub fn gree()  -> Color { Color{ r: 0.0, g: 1.0, b: 0.0, a: 1.0 } }
     pub fn blue()  -> Color { Color{ r: 0.0, g: 0.0, b: 1.0, a: 1.0 } }
+
+    // Linear interpolation between two colors, t in [0,1].
+    pub fn lerp(from: Color, to: Color, t: f32) -> Color {
+        Color{ r: from.r + (to.r - from.r) * t,
+               g: from.g + (to.g - from.g) * t,
+               b: from.b + (to.b - from.b) * t,
+               a: from.a + (to.a - from.a) * t }
+    }
+}
+
+// ----------------------------------------------
+// Background
+// ----------------------------------------------
+
+// What gets drawn behind all the tiles, visible wherever no tile covers the window.
+#[derive(Copy, Clone)]
+pub enum Background {
+    Solid(Color),
+    VerticalGradient(Color, Color), // Top color, bottom color.
 }
 
 // ----------------------------------------------
@@ -74,6 +91,32 @@ impl Rect2d {
     pub fn width(&self)  -> i32 { self.maxs.x - self.mins.x }
     pub fn height(&self) -> i32 { self.maxs.y - self.mins.y }
     pub fn area(&self)   -> i32 { self.width() * self.height() }
+
+    // Mins inclusive, maxs exclusive.
+    pub fn contains(&self, point: Point2d) -> bool {
+        point.x >= self.mins.x && point.x < self.maxs.x &&
+        point.y >= self.mins.y && point.y < self.maxs.y
+    }
+}
+
+// ----------------------------------------------
+// Hashing helpers
+// ----------------------------------------------
+
+// Cheap and stable integer hash of a map cell. The same cell and seed
+// always produce the same value, so it is safe to use for choices that
+// must be reproducible between runs (e.g. picking tile variants).
+pub fn hash_cell(cell: Point2d, seed: u32) -> u32 {
+    let mut h = seed ^ 0x9E3779B9;
+    h = (h ^ (cell.x as u32)).wrapping_mul(0x85EBCA6B);
+    h = (h ^ (cell.y as u32)).wrapping_mul(0xC2B2AE35);
+    // MurmurHash3 finalizer:
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85EBCA6B);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xC2B2AE35);
+    h ^= h >> 16;
+    h
 }
 
 // ----------------------------------------------
@@ -88,7 +131,6 @@ pub struct Config {
 // of the parameters from an external XML file...
 impl Config {
     pub fn new() -> Config {
-        Config::pwd();
         println!("Initializing runtime configurations...");
         Config{ version: 1.0 }
     }
@@ -99,16 +141,23 @@ impl Config {
     pub fn get_texture_atlases(&self) -> &'static [&'static str] {
         TEXTURE_ATLASES
     }
+    pub fn get_tile_variant_seed(&self) -> u32 {
+        TILE_VARIANT_SEED
+    }
+    pub fn get_default_background(&self) -> Background {
+        DEFAULT_BACKGROUND
+    }
     pub fn get_tile_draw_fs(&self) -> &'static str {
         TILE_FRAGMENT_SHADER_SRC
     }
     pub fn get_tile_draw_vs(&self) -> &'static str {
         TILE_VERTEX_SHADER_SRC
     }
-
-    fn pwd() {
-        let cwd = std::env::current_dir().unwrap();
-        println!("The current directory is \"{}\".", cwd.display());
+    pub fn get_backdrop_draw_fs(&self) -> &'static str {
+        BACKDROP_FRAGMENT_SHADER_SRC
+    }
+    pub fn get_backdrop_draw_vs(&self) -> &'static str {
+        BACKDROP_VERTEX_SHADER_SRC
     }
 }
 
@@ -116,6 +165,18 @@ impl Config {
 // Miscellaneous compile-time constants:
 // ----------------------------------------------
 
+// Capacity of the BatchRenderer GPU buffers. Adding more
+// tiles than fit in a batch is a fatal error.
+pub const BATCH_VB_SIZE: usize = 2048; // Size in DrawVertexes
+pub const BATCH_IB_SIZE: usize = 4096; // Size in DrawIndexes
+
+pub static ASSET_BUNDLE_FILE_NAME:      &'static str = "assets.tar";
+pub static ASSET_ROOT_MAX_SEARCH_DEPTH: i32 = 3; // Parent dirs of the executable searched for assets.
+
+pub static FRAME_CLOCK_MAX_DELTA_SECS:  f32 = 0.25; // Longest frame step the game time line will take.
+
+pub static TILE_TINT_PULSE_HZ:          f32 = 2.0; // Blinks per second of a pulsing tile tint.
+
 pub static TEXTURE_ATLAS_BASE_PATH:     &'static str = "atlases";
 pub static TEXTURE_ATLAS_META_FILE_EXT: &'static str = ".xml";
 pub static TEXTURE_ATLAS_TEX_FILE_EXT:  &'static str = ".png";
@@ -124,6 +185,14 @@ static TEXTURE_ATLASES: &'static [&'static str] = &[
     "house-tileset",
 ];
 
+// Background the BatchRenderer starts with. Maps can replace it at
+// load time with BatchRenderer::set_background().
+static DEFAULT_BACKGROUND: Background = Background::Solid(Color{ r: 0.1, g: 0.1, b: 0.1, a: 1.0 });
+
+// Seed mixed into the per-cell hash that selects tile variants.
+// Changing it reshuffles every variant tile on the map.
+static TILE_VARIANT_SEED: u32 = 1337;
+
 // ----------------------------------------------
 // Inline GLSL shaders:
 // ----------------------------------------------
@@ -165,3 +234,30 @@ const TILE_FRAGMENT_SHADER_SRC: &'static str = r#"
         frag_color = texture(texture_sampler, v_tex_coords) * v_color;
     }
 "#;
+
+// Untextured full-screen quad for the background. Positions
+// are already in clip space; the colors are interpolated.
+const BACKDROP_VERTEX_SHADER_SRC: &'static str = r#"
+    #version 150
+
+    in vec2 position;
+    in vec4 color;
+
+    out vec4 v_color;
+
+    void main() {
+        v_color     = color;
+        gl_Position = vec4(position, 0.0, 1.0);
+    }
+"#;
+
+const BACKDROP_FRAGMENT_SHADER_SRC: &'static str = r#"
+    #version 150
+
+    in vec4 v_color;
+    out vec4 frag_color;
+
+    void main() {
+        frag_color = v_color;
+    }
+"#;
@@ -0,0 +1,262 @@
+// ================================================================================================
+// File: input.rs
+// Author: Guilherme R. Lampert
+// Created on: 16/10/26
+// Brief: Keyboard and mouse input state tracking, hardware cursor control.
+//
+// This source code is released under the MIT license.
+// See the accompanying LICENSE file for details.
+// ================================================================================================
+
+use glium::Display;
+use glium::glutin::{Event, ElementState, VirtualKeyCode, MouseButton, MouseCursor, CursorState};
+use citysim::common::Point2d;
+
+// ----------------------------------------------
+// InputTransitions
+// ----------------------------------------------
+
+// Held set plus the pressed/released edges seen since the last frame.
+// Repeated press events for something already held (key repeat) are not
+// reported as new presses.
+struct InputTransitions<T> {
+    held:     Vec<T>,
+    pressed:  Vec<T>,
+    released: Vec<T>,
+}
+
+impl<T: Copy + PartialEq> InputTransitions<T> {
+    fn new() -> InputTransitions<T> {
+        InputTransitions{ held: Vec::new(), pressed: Vec::new(), released: Vec::new() }
+    }
+
+    fn press(&mut self, input: T) {
+        if !self.held.contains(&input) {
+            self.held.push(input);
+            self.pressed.push(input);
+        }
+    }
+
+    fn release(&mut self, input: T) {
+        if let Some(index) = self.held.iter().position(|h| *h == input) {
+            self.held.swap_remove(index);
+            self.released.push(input);
+        }
+    }
+
+    fn release_all(&mut self) {
+        self.released.extend_from_slice(&self.held);
+        self.held.clear();
+    }
+
+    fn clear_edges(&mut self) {
+        self.pressed.clear();
+        self.released.clear();
+    }
+}
+
+// ----------------------------------------------
+// InputState
+// ----------------------------------------------
+
+// Tracks keyboard and mouse state from the window events. Call begin_frame()
+// before polling the window events each frame, then feed every event to
+// handle_event(). The was_*() queries report edges for the current frame only.
+//
+// While text input is active, characters typed this frame are collected and
+// can be read with get_typed_text(). The text field owns the keyboard then, so
+// the key queries only report the editing keys (see is_text_editing_key()).
+// That way typing into a field doesn't also trigger game shortcuts.
+pub struct InputState {
+    keys:        InputTransitions<VirtualKeyCode>,
+    buttons:     InputTransitions<MouseButton>,
+    cursor_pos:  Point2d,
+    text_active: bool,
+    typed_text:  String,
+}
+
+impl InputState {
+    pub fn new() -> InputState {
+        InputState{
+            keys:        InputTransitions::new(),
+            buttons:     InputTransitions::new(),
+            cursor_pos:  Point2d::new(),
+            text_active: false,
+            typed_text:  String::new(),
+        }
+    }
+
+    pub fn begin_frame(&mut self) {
+        self.keys.clear_edges();
+        self.buttons.clear_edges();
+        self.typed_text.clear();
+    }
+
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::KeyboardInput(ElementState::Pressed,  _, Some(key)) => self.keys.press(key),
+            Event::KeyboardInput(ElementState::Released, _, Some(key)) => self.keys.release(key),
+            Event::MouseInput(ElementState::Pressed,  button)          => self.buttons.press(button),
+            Event::MouseInput(ElementState::Released, button)          => self.buttons.release(button),
+            Event::MouseMoved((x, y))                                  => self.cursor_pos = Point2d::with_coords(x, y),
+            Event::ReceivedCharacter(c)                                => self.add_typed_char(c),
+            // We won't get the release events while unfocused, so drop everything now.
+            Event::Focused(false) => {
+                self.keys.release_all();
+                self.buttons.release_all();
+            }
+            _ => {}
+        }
+    }
+
+    pub fn was_pressed(&self, key: VirtualKeyCode) -> bool {
+        self.is_key_visible(key) && self.keys.pressed.contains(&key)
+    }
+    pub fn was_released(&self, key: VirtualKeyCode) -> bool {
+        self.is_key_visible(key) && self.keys.released.contains(&key)
+    }
+    pub fn is_held(&self, key: VirtualKeyCode) -> bool {
+        self.is_key_visible(key) && self.keys.held.contains(&key)
+    }
+
+    pub fn was_button_pressed(&self, button: MouseButton) -> bool {
+        self.buttons.pressed.contains(&button)
+    }
+    pub fn was_button_released(&self, button: MouseButton) -> bool {
+        self.buttons.released.contains(&button)
+    }
+    pub fn is_button_held(&self, button: MouseButton) -> bool {
+        self.buttons.held.contains(&button)
+    }
+
+    pub fn get_cursor_pos(&self) -> Point2d {
+        self.cursor_pos
+    }
+
+    // Only one text field can own the keyboard at a time, so whoever
+    // calls start_text_input() is responsible for stopping it later.
+    pub fn start_text_input(&mut self) {
+        self.text_active = true;
+        self.typed_text.clear();
+    }
+    pub fn stop_text_input(&mut self) {
+        self.text_active = false;
+        self.typed_text.clear();
+    }
+    pub fn is_text_input_active(&self) -> bool {
+        self.text_active
+    }
+    pub fn get_typed_text(&self) -> &str {
+        &self.typed_text
+    }
+
+    // Keys a text field needs that don't come through as typed characters.
+    // Modifiers are included for selection and shortcuts (Shift+arrows, Ctrl+C, etc).
+    pub fn is_text_editing_key(key: VirtualKeyCode) -> bool {
+        match key {
+            VirtualKeyCode::Back     | VirtualKeyCode::Delete   |
+            VirtualKeyCode::Return   | VirtualKeyCode::Tab      |
+            VirtualKeyCode::Left     | VirtualKeyCode::Right    |
+            VirtualKeyCode::Up       | VirtualKeyCode::Down     |
+            VirtualKeyCode::Home     | VirtualKeyCode::End      |
+            VirtualKeyCode::LShift   | VirtualKeyCode::RShift   |
+            VirtualKeyCode::LControl | VirtualKeyCode::RControl |
+            VirtualKeyCode::LAlt     | VirtualKeyCode::RAlt     |
+            VirtualKeyCode::LWin     | VirtualKeyCode::RWin     |
+            VirtualKeyCode::Escape => true,
+            _ => false,
+        }
+    }
+
+    fn is_key_visible(&self, key: VirtualKeyCode) -> bool {
+        !self.text_active || InputState::is_text_editing_key(key)
+    }
+
+    fn add_typed_char(&mut self, c: char) {
+        if self.text_active && !c.is_control() {
+            self.typed_text.push(c);
+        }
+    }
+}
+
+// ----------------------------------------------
+// CursorControl
+// ----------------------------------------------
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CursorIcon {
+    Arrow,
+    Hand,
+    Crosshair,
+    Forbidden,
+}
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum CursorCapture {
+    Free,    // Regular OS cursor.
+    Hidden,  // Invisible while over the window, but free to leave it.
+    Grabbed, // Confined to the window.
+}
+
+// Hardware cursor icon and capture mode of the game window. Setting the
+// current state again is a no-op, so tools can just set what they want
+// every frame (e.g. Forbidden while a placement is invalid).
+pub struct CursorControl {
+    icon:    CursorIcon,
+    capture: CursorCapture,
+}
+
+impl CursorControl {
+    pub fn new() -> CursorControl {
+        CursorControl{ icon: CursorIcon::Arrow, capture: CursorCapture::Free }
+    }
+
+    pub fn get_icon(&self) -> CursorIcon {
+        self.icon
+    }
+    pub fn get_capture(&self) -> CursorCapture {
+        self.capture
+    }
+
+    pub fn set_icon(&mut self, display: &Display, icon: CursorIcon) {
+        if icon == self.icon {
+            return;
+        }
+        if let Some(window) = display.get_window() {
+            window.set_cursor(CursorControl::to_mouse_cursor(icon));
+            self.icon = icon;
+        }
+    }
+
+    // Not every platform supports every capture mode. Returns false if the mode couldn't be set.
+    pub fn set_capture(&mut self, display: &Display, capture: CursorCapture) -> bool {
+        if capture == self.capture {
+            return true;
+        }
+        let window = match display.get_window() {
+            Some(window) => window,
+            None         => return false,
+        };
+        match window.set_cursor_state(CursorControl::to_cursor_state(capture)) {
+            Ok(_)      => { self.capture = capture; true }
+            Err(error) => { println!("Failed to change the cursor capture mode: {}", error); false }
+        }
+    }
+
+    fn to_mouse_cursor(icon: CursorIcon) -> MouseCursor {
+        match icon {
+            CursorIcon::Arrow     => MouseCursor::Default,
+            CursorIcon::Hand      => MouseCursor::Hand,
+            CursorIcon::Crosshair => MouseCursor::Crosshair,
+            CursorIcon::Forbidden => MouseCursor::NotAllowed,
+        }
+    }
+
+    fn to_cursor_state(capture: CursorCapture) -> CursorState {
+        match capture {
+            CursorCapture::Free    => CursorState::Normal,
+            CursorCapture::Hidden  => CursorState::Hide,
+            CursorCapture::Grabbed => CursorState::Grab,
+        }
+    }
+}
@@ -9,7 +9,10 @@
 // See the accompanying LICENSE file for details.
 // ================================================================================================
 
+pub mod assets;
+pub mod clock;
 pub mod common;
+pub mod input;
 pub mod render;
 pub mod texcache;
 pub mod tile;
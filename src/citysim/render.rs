@@ -11,9 +11,11 @@
 
 extern crate glium;
 
+use std::mem;
 use glium::Surface;
-use citysim::texcache::TextureCache;
-use citysim::common::Config;
+use glium::draw_parameters::TimeElapsedQuery;
+use citysim::texcache::{TextureCache, TexId, TEX_ID_NONE};
+use citysim::common::{Config, Background, BATCH_VB_SIZE, BATCH_IB_SIZE};
 use citysim::tile::{Tile, TileGeometry};
 
 // ----------------------------------------------
@@ -30,12 +32,102 @@ pub struct DrawVertex {
 }
 implement_vertex!(DrawVertex, position, tex_coords, color);
 
+// ----------------------------------------------
+// RenderLayer
+// ----------------------------------------------
+
+// Render passes, drawn back to front in declaration order.
+#[derive(Copy, Clone, PartialEq)]
+pub enum RenderLayer {
+    Terrain,      // Ground tiles.
+    CellOverlays, // Selection highlights and per-cell overlays, above terrain but beneath objects.
+    Objects,      // Buildings, props and units.
+    ScreenUi,     // Screen-space UI, always on top.
+}
+
+pub const RENDER_LAYER_COUNT: usize = 4;
+
+const RENDER_LAYERS: [RenderLayer; RENDER_LAYER_COUNT] = [
+    RenderLayer::Terrain,
+    RenderLayer::CellOverlays,
+    RenderLayer::Objects,
+    RenderLayer::ScreenUi,
+];
+
+// ----------------------------------------------
+// RenderStats
+// ----------------------------------------------
+
+#[derive(Copy, Clone)]
+pub struct RenderPassStats {
+    pub draw_calls:    u32,
+    pub tiles_drawn:   u32,
+    pub texture_binds: u32, // Texture changes, not draw calls. Drawing with the texture already bound isn't counted.
+    pub gpu_time_ns:   u32, // GPU time of the pass. Lags a frame behind; stays 0 if timer queries are unsupported.
+}
+
+impl RenderPassStats {
+    pub fn new() -> RenderPassStats {
+        RenderPassStats{ draw_calls: 0, tiles_drawn: 0, texture_binds: 0, gpu_time_ns: 0 }
+    }
+}
+
+// Counters gathered by the BatchRenderer. Upload sizes are refreshed
+// by update(), everything else by each call to draw().
+#[derive(Clone)]
+pub struct RenderStats {
+    pub passes:              [RenderPassStats; RENDER_LAYER_COUNT], // Indexed by RenderLayer.
+    pub texture_binds:       Vec<u32>,                              // Binds per texture atlas, indexed by TexId.
+    pub vertex_upload_bytes: usize,
+    pub index_upload_bytes:  usize,
+}
+
+impl RenderStats {
+    pub fn new(tex_count: usize) -> RenderStats {
+        RenderStats{
+            passes:              [RenderPassStats::new(); RENDER_LAYER_COUNT],
+            texture_binds:       vec![0; tex_count],
+            vertex_upload_bytes: 0,
+            index_upload_bytes:  0,
+        }
+    }
+
+    pub fn get_pass(&self, layer: RenderLayer) -> &RenderPassStats {
+        &self.passes[layer as usize]
+    }
+
+    pub fn total_draw_calls(&self) -> u32 {
+        self.passes.iter().fold(0, |sum, pass| sum + pass.draw_calls)
+    }
+
+    pub fn total_upload_bytes(&self) -> usize {
+        self.vertex_upload_bytes + self.index_upload_bytes
+    }
+
+    pub fn total_gpu_time_ns(&self) -> u32 {
+        self.passes.iter().fold(0, |sum, pass| sum + pass.gpu_time_ns)
+    }
+
+    // GPU timings are kept; they are updated separately as the query results arrive.
+    fn reset_draw_counters(&mut self) {
+        for pass in self.passes.iter_mut() {
+            pass.draw_calls    = 0;
+            pass.tiles_drawn   = 0;
+            pass.texture_binds = 0;
+        }
+        for binds in self.texture_binds.iter_mut() {
+            *binds = 0;
+        }
+    }
+}
+
 // ----------------------------------------------
 // BatchRenderer
 // ----------------------------------------------
 
-const BATCH_VB_SIZE: usize = 2048; // Size in DrawVertexs
-const BATCH_IB_SIZE: usize = 4096; // Size in DrawIndexes
+// Vertexes are referenced by DrawIndex (u16), so a batch vertex buffer bigger than
+// that could address would silently wrap the indexes. Refuse to compile instead.
+const BATCH_VB_SIZE_FITS_DRAW_INDEX: [(); 0] = [(); (BATCH_VB_SIZE > ::std::u16::MAX as usize + 1) as usize];
 
 #[derive(Clone)]
 struct BatchBucket {
@@ -55,13 +147,18 @@ impl BatchBucket {
 }
 
 pub struct BatchRenderer {
-    texture_buckets: Vec<BatchBucket>,
+    layer_buckets:   Vec<Vec<BatchBucket>>, // [layer][tex_id]
     shader_prog:     glium::Program,
     vertex_buffer:   glium::VertexBuffer<DrawVertex>,
     index_buffer:    glium::IndexBuffer<DrawIndex>,
     local_verts:     Vec<DrawVertex>,
     local_indexes:   Vec<DrawIndex>,
     tile_count:      u32,
+    stats:           RenderStats,
+    pass_timers:     Vec<Option<TimeElapsedQuery>>, // One GPU timer per RenderLayer, issued by the last draw().
+    background:      Background,
+    backdrop_prog:   glium::Program,
+    backdrop_verts:  glium::VertexBuffer<DrawVertex>, // Full-screen quad for gradient backgrounds.
 }
 
 impl BatchRenderer {
@@ -71,31 +168,62 @@ impl BatchRenderer {
         let prim = glium::index::PrimitiveType::TrianglesList;
         let vb   = glium::VertexBuffer::empty_dynamic(facade, BATCH_VB_SIZE).unwrap();
         let ib   = glium::IndexBuffer::empty_dynamic(facade, prim, BATCH_IB_SIZE).unwrap();
+        let bg   = glium::VertexBuffer::empty_dynamic(facade, 4).unwrap();
+
+        let tex_count = tex_cache.get_tex_count() as usize;
 
-        let mut buckets = Vec::new();
-        buckets.resize(tex_cache.get_tex_count() as usize, BatchBucket::new());
+        let mut texture_buckets = Vec::new();
+        texture_buckets.resize(tex_count, BatchBucket::new());
+
+        let mut layer_buckets = Vec::new();
+        layer_buckets.resize(RENDER_LAYER_COUNT, texture_buckets);
         println!("BatchRenderer created!");
 
         BatchRenderer{
-            texture_buckets: buckets,
+            layer_buckets:   layer_buckets,
             shader_prog:     BatchRenderer::make_shader_prog(facade, config),
             vertex_buffer:   vb,
             index_buffer:    ib,
             local_verts:     Vec::with_capacity(BATCH_VB_SIZE),
             local_indexes:   Vec::with_capacity(BATCH_IB_SIZE),
             tile_count:      0,
+            stats:           RenderStats::new(tex_count),
+            pass_timers:     (0 .. RENDER_LAYER_COUNT).map(|_| None).collect(),
+            background:      config.get_default_background(),
+            backdrop_prog:   BatchRenderer::make_backdrop_prog(facade, config),
+            backdrop_verts:  bg,
         }
     }
 
-    pub fn add_tile(&mut self, tile: &Tile) {
+    pub fn get_stats(&self) -> &RenderStats {
+        &self.stats
+    }
+
+    pub fn get_background(&self) -> Background {
+        self.background
+    }
+    // For map/scenario loaders to replace the default background.
+    pub fn set_background(&mut self, background: Background) {
+        self.background = background;
+    }
+
+    pub fn add_tile(&mut self, tile: &Tile, layer: RenderLayer) {
         let bucket_index = tile.tex_id as usize;
-        self.texture_buckets[bucket_index].geometry.push(tile.geometry);
+        self.layer_buckets[layer as usize][bucket_index].geometry.push(tile.geometry);
         self.tile_count += 1;
     }
 
+    // Overlay quads for a map cell (selection, highlights, etc) are
+    // drawn above the terrain but beneath any objects on the cell.
+    pub fn add_cell_overlay(&mut self, overlay: &Tile) {
+        self.add_tile(overlay, RenderLayer::CellOverlays);
+    }
+
     pub fn clear(&mut self) {
-        for bucket in &mut self.texture_buckets {
-            bucket.clear();
+        for buckets in &mut self.layer_buckets {
+            for bucket in buckets {
+                bucket.clear();
+            }
         }
         self.local_verts.clear();
         self.local_indexes.clear();
@@ -106,18 +234,25 @@ impl BatchRenderer {
         let base_indexes = &[0, 1, 2,  2, 3, 0];
         let mut base_vertex = 0;
 
-        // Assemble the quadrilaterals:
-        for bucket in &mut self.texture_buckets {
-            bucket.index_buffer_slice.0 = self.local_indexes.len() as u32;
-            for entry in &mut bucket.geometry {
-                let quad = BatchRenderer::make_quad_verts(entry);
-                self.local_verts.extend_from_slice(&quad);
-                for idx in base_indexes {
-                    self.local_indexes.push((idx + base_vertex) as DrawIndex);
+        // The local lists are scratch buffers rebuilt on every update.
+        // Clearing keeps their capacity, so no reallocation after the first frame.
+        self.local_verts.clear();
+        self.local_indexes.clear();
+
+        // Assemble the quadrilaterals, layer by layer:
+        for buckets in &mut self.layer_buckets {
+            for bucket in buckets {
+                bucket.index_buffer_slice.0 = self.local_indexes.len() as u32;
+                for entry in &mut bucket.geometry {
+                    let quad = BatchRenderer::make_quad_verts(entry);
+                    self.local_verts.extend_from_slice(&quad);
+                    for idx in base_indexes {
+                        self.local_indexes.push((idx + base_vertex) as DrawIndex);
+                    }
+                    base_vertex += 4;
                 }
-                base_vertex += 4;
+                bucket.index_buffer_slice.1 = self.local_indexes.len() as u32;
             }
-            bucket.index_buffer_slice.1 = self.local_indexes.len() as u32;
         }
 
         if self.local_verts.len() > BATCH_VB_SIZE {
@@ -127,6 +262,9 @@ impl BatchRenderer {
             panic!("BATCH_IB_SIZE exceeded!!!");
         }
 
+        self.stats.vertex_upload_bytes = self.local_verts.len()   * mem::size_of::<DrawVertex>();
+        self.stats.index_upload_bytes  = self.local_indexes.len() * mem::size_of::<DrawIndex>();
+
         // Upload to the GL:
         let mut buffer_index = 0;
         let mut vb_mapping = self.vertex_buffer.map_write();
@@ -143,11 +281,30 @@ impl BatchRenderer {
         }
     }
 
-    pub fn draw(&self, target: &mut glium::Frame, tex_cache: &TextureCache) {
+    pub fn draw<F>(&mut self, facade: &F, target: &mut glium::Frame, tex_cache: &TextureCache)
+                   where F: glium::backend::Facade {
+
+        // Also clears the frame, so it is drawn even if there are no tiles.
+        self.draw_background(target);
+
+        // Timer results are read a frame late so we don't stall waiting on the GPU.
+        self.collect_pass_timings();
+        self.stats.reset_draw_counters();
         if self.tile_count == 0 {
             return; // Nothing to draw.
         }
 
+        // A new query for each pass. Queries are single-use in GL, and
+        // creation fails harmlessly if the driver doesn't support them.
+        for layer in RENDER_LAYERS.iter() {
+            let index = *layer as usize;
+            if self.layer_buckets[index].iter().any(|bucket| !bucket.geometry.is_empty()) {
+                self.pass_timers[index] = TimeElapsedQuery::new(facade).ok();
+            } else {
+                self.stats.passes[index].gpu_time_ns = 0;
+            }
+        }
+
         let draw_params = glium::DrawParameters{
             blend: glium::Blend::alpha_blending(),
             .. Default::default()
@@ -156,19 +313,93 @@ impl BatchRenderer {
         let screen_dimensions = (target.get_dimensions().0 as f32,
                                  target.get_dimensions().1 as f32);
 
-        // One draw call for each texture:
-        let mut tex_id = 0;
-        for bucket in &self.texture_buckets {
-            let uniforms = uniform!{
-                screen_dimensions: screen_dimensions,
-                texture_sampler: &tex_cache.get_tex_from_id(tex_id).unwrap().tex,
+        // Layers are drawn back to front, one draw call for each texture in the layer:
+        let mut bound_tex_id = TEX_ID_NONE;
+        for layer in RENDER_LAYERS.iter() {
+            let layer_params = glium::DrawParameters{
+                time_elapsed_query: self.pass_timers[*layer as usize].as_ref(),
+                .. draw_params.clone()
             };
+            BatchRenderer::draw_layer(target, tex_cache, &self.layer_buckets[*layer as usize],
+                                      &self.vertex_buffer, &self.index_buffer, &self.shader_prog,
+                                      *layer, screen_dimensions, &layer_params, &mut bound_tex_id, &mut self.stats);
+        }
+
+        // Each pass's query is ended when the next pass starts its own. is_ready() also
+        // ends a query that is still running, so the last pass doesn't time the rest of the frame.
+        for timer in self.pass_timers.iter() {
+            if let Some(ref query) = *timer {
+                query.is_ready();
+            }
+        }
+    }
+
+    fn draw_background(&self, target: &mut glium::Frame) {
+        match self.background {
+            Background::Solid(color) => {
+                target.clear_color(color.r, color.g, color.b, color.a);
+            }
+            Background::VerticalGradient(top, bottom) => {
+                let top_rgba    = [ top.r,    top.g,    top.b,    top.a    ];
+                let bottom_rgba = [ bottom.r, bottom.g, bottom.b, bottom.a ];
+                self.backdrop_verts.write(&[
+                    DrawVertex{ position: [-1.0,  1.0], tex_coords: [0.0, 0.0], color: top_rgba    },
+                    DrawVertex{ position: [-1.0, -1.0], tex_coords: [0.0, 1.0], color: bottom_rgba },
+                    DrawVertex{ position: [ 1.0, -1.0], tex_coords: [1.0, 1.0], color: bottom_rgba },
+                    DrawVertex{ position: [ 1.0,  1.0], tex_coords: [1.0, 0.0], color: top_rgba    },
+                ]);
+                let indexes = glium::index::NoIndices(glium::index::PrimitiveType::TriangleFan);
+                target.draw(&self.backdrop_verts, &indexes, &self.backdrop_prog,
+                            &glium::uniforms::EmptyUniforms, &Default::default()).unwrap();
+            }
+        }
+    }
+
+    fn collect_pass_timings(&mut self) {
+        for (index, timer) in self.pass_timers.iter_mut().enumerate() {
+            if let Some(query) = timer.take() {
+                // A result that isn't available yet is dropped and the previous timing kept.
+                if query.is_ready() {
+                    self.stats.passes[index].gpu_time_ns = query.get();
+                }
+            }
+        }
+    }
+
+    // Takes the renderer fields it needs as separate borrows, so
+    // draw() can pass in its stats mutably alongside the buffers.
+    fn draw_layer(target: &mut glium::Frame, tex_cache: &TextureCache, buckets: &[BatchBucket],
+                  vertex_buffer: &glium::VertexBuffer<DrawVertex>, index_buffer: &glium::IndexBuffer<DrawIndex>,
+                  shader_prog: &glium::Program, layer: RenderLayer, screen_dimensions: (f32, f32),
+                  draw_params: &glium::DrawParameters, bound_tex_id: &mut TexId, stats: &mut RenderStats) {
 
-            let start = bucket.index_buffer_slice.0 as usize;
-            let end   = bucket.index_buffer_slice.1 as usize;
-            let slice = self.index_buffer.slice(start .. end).unwrap();
+        let mut tex_id = 0;
+        for bucket in buckets {
+            if !bucket.geometry.is_empty() {
+                let uniforms = uniform!{
+                    screen_dimensions: screen_dimensions,
+                    texture_sampler: &tex_cache.get_tex_from_id(tex_id).unwrap().tex,
+                };
+
+                let start = bucket.index_buffer_slice.0 as usize;
+                let end   = bucket.index_buffer_slice.1 as usize;
+                let slice = index_buffer.slice(start .. end).unwrap();
+
+                target.draw(vertex_buffer, &slice, shader_prog, &uniforms, draw_params).unwrap();
+
+                let rebind = tex_id != *bound_tex_id;
+                if rebind {
+                    *bound_tex_id = tex_id;
+                    stats.texture_binds[tex_id as usize] += 1;
+                }
 
-            target.draw(&self.vertex_buffer, &slice, &self.shader_prog, &uniforms, &draw_params).unwrap();
+                let pass = &mut stats.passes[layer as usize];
+                pass.draw_calls  += 1;
+                pass.tiles_drawn += bucket.geometry.len() as u32;
+                if rebind {
+                    pass.texture_binds += 1;
+                }
+            }
             tex_id += 1;
         }
     }
@@ -193,4 +424,11 @@ impl BatchRenderer {
                         config.get_tile_draw_vs(),
                         config.get_tile_draw_fs(), None).unwrap()
     }
+
+    fn make_backdrop_prog<F>(facade: &F, config: &Config) -> glium::Program
+                             where F: glium::backend::Facade {
+        glium::Program::from_source(facade,
+                        config.get_backdrop_draw_vs(),
+                        config.get_backdrop_draw_fs(), None).unwrap()
+    }
 }
@@ -13,12 +13,11 @@ extern crate xml;
 extern crate image;
 extern crate glium;
 
-use std;
-use std::path::Path;
-use std::fs::File;
-use std::io::BufReader;
+use std::collections::HashMap;
+use xml::common::{Position, TextPosition};
 use xml::reader::{EventReader, XmlEvent};
 
+use citysim::assets::AssetLoader;
 use citysim::common::*;
 use citysim::tile::{Tile, TileGeometry};
 
@@ -28,6 +27,7 @@ use citysim::tile::{Tile, TileGeometry};
 
 pub struct TexAtlasSubTexture {
     pub filename:     String, // Original image that made this sub-texture.
+    pub variant:      String, // Logical tile this is an art variant of. Empty if none.
     pub x:            i32,
     pub y:            i32,
     pub width:        i32,
@@ -36,23 +36,28 @@ pub struct TexAtlasSubTexture {
     pub frame_y:      i32,
     pub frame_width:  i32,
     pub frame_height: i32,
+    pub xml_position: TextPosition, // Where the SubTexture element is in the atlas XML, for error reports.
 }
 
 impl TexAtlasSubTexture {
     pub fn new() -> TexAtlasSubTexture {
         TexAtlasSubTexture{
             filename: String::new(),
+            variant:  String::new(),
             x:           0, y:            0,
             width:       0, height:       0,
             frame_x:     0, frame_y:      0,
             frame_width: 0, frame_height: 0,
+            xml_position: TextPosition::new(),
         }
     }
 }
 
 pub struct TextureAtlas {
-    tex_filename: String,
-    sub_textures: Vec<TexAtlasSubTexture>,
+    tex_filename:          String,
+    tex_filename_position: TextPosition,              // Where imagePath was set in the atlas XML, for error reports.
+    sub_textures:          Vec<TexAtlasSubTexture>,
+    variants:              HashMap<String, Vec<i32>>, // Logical tile name => sub-texture indexes. Built once on parse.
 }
 
 impl TextureAtlas {
@@ -64,56 +69,149 @@ impl TextureAtlas {
         self.sub_textures.len() as i32
     }
 
+    // Indexes of all sub-textures that are art variants of the given logical tile.
+    pub fn get_variants(&self, variant: &str) -> &[i32] {
+        match self.variants.get(variant) {
+            Some(indexes) => indexes,
+            None          => &[],
+        }
+    }
+
+    // Deterministically picks one of the variants of a logical tile for the given
+    // map cell. Same cell + seed always yields the same sub-texture index.
+    pub fn pick_variant(&self, variant: &str, cell: Point2d, seed: u32) -> Option<i32> {
+        let variants = self.get_variants(variant);
+        if variants.is_empty() {
+            return None;
+        }
+        let choice = hash_cell(cell, seed) as usize % variants.len();
+        Some(variants[choice])
+    }
+
     pub fn empty() -> TextureAtlas {
-        TextureAtlas{ tex_filename: String::new(), sub_textures: Vec::new() }
+        TextureAtlas{
+            tex_filename:          String::new(),
+            tex_filename_position: TextPosition::new(),
+            sub_textures:          Vec::new(),
+            variants:              HashMap::new(),
+        }
     }
 
-    pub fn parse_from_xml(xml_filename: &str) -> TextureAtlas {
-        let xml_file    = File::open(xml_filename).unwrap();
-        let file_reader = BufReader::new(xml_file);
-        let xml_parser  = EventReader::new(file_reader);
-        let mut atlas   = TextureAtlas{ tex_filename: String::new(), sub_textures: Vec::new() };
+    // Parse errors are appended to 'errors' (prefixed with file:line:column)
+    // rather than aborting, so that every problem can be reported at once.
+    pub fn parse_from_xml(xml_data: &[u8], xml_filename: &str, errors: &mut Vec<String>) -> TextureAtlas {
+        let mut xml_parser = EventReader::new(xml_data);
+        let mut atlas      = TextureAtlas::empty();
+
+        loop {
+            let event    = xml_parser.next();
+            let position = xml_parser.position();
+            let location = format!("{}:{}", xml_filename, position);
 
-        for event in xml_parser {
             match event {
                 Ok(XmlEvent::StartElement{ name, attributes, .. }) => {
                     if name.local_name == "SubTexture" {
                         let mut sub_tex = TexAtlasSubTexture::new();
+                        sub_tex.xml_position = position;
                         for attr in attributes {
                             let attr_name = &attr.name.local_name;
+                            let value     = &attr.value;
                             match attr_name.as_ref() {
-                                "name"        => sub_tex.filename     = attr.value,
-                                "x"           => sub_tex.x            = attr.value.parse::<f32>().unwrap() as i32,
-                                "y"           => sub_tex.y            = attr.value.parse::<f32>().unwrap() as i32,
-                                "width"       => sub_tex.width        = attr.value.parse::<f32>().unwrap() as i32,
-                                "height"      => sub_tex.height       = attr.value.parse::<f32>().unwrap() as i32,
-                                "frameX"      => sub_tex.frame_x      = attr.value.parse::<f32>().unwrap() as i32,
-                                "frameY"      => sub_tex.frame_y      = attr.value.parse::<f32>().unwrap() as i32,
-                                "frameWidth"  => sub_tex.frame_width  = attr.value.parse::<f32>().unwrap() as i32,
-                                "frameHeight" => sub_tex.frame_height = attr.value.parse::<f32>().unwrap() as i32,
+                                "name"        => sub_tex.filename     = value.clone(),
+                                "variant"     => sub_tex.variant      = value.clone(),
+                                "x"           => sub_tex.x            = parse_int_attr(value, attr_name, &location, errors),
+                                "y"           => sub_tex.y            = parse_int_attr(value, attr_name, &location, errors),
+                                "width"       => sub_tex.width        = parse_int_attr(value, attr_name, &location, errors),
+                                "height"      => sub_tex.height       = parse_int_attr(value, attr_name, &location, errors),
+                                "frameX"      => sub_tex.frame_x      = parse_int_attr(value, attr_name, &location, errors),
+                                "frameY"      => sub_tex.frame_y      = parse_int_attr(value, attr_name, &location, errors),
+                                "frameWidth"  => sub_tex.frame_width  = parse_int_attr(value, attr_name, &location, errors),
+                                "frameHeight" => sub_tex.frame_height = parse_int_attr(value, attr_name, &location, errors),
                                 _             => {},
                             }
                         }
+                        if sub_tex.filename.is_empty() {
+                            errors.push(format!("{}: SubTexture is missing a 'name' attribute.", location));
+                        }
+                        if sub_tex.width <= 0 || sub_tex.height <= 0 {
+                            errors.push(format!("{}: SubTexture '{}' has invalid size {}x{}.",
+                                                location, sub_tex.filename, sub_tex.width, sub_tex.height));
+                        }
                         atlas.sub_textures.push(sub_tex);
                     } else if name.local_name == "TextureAtlas" {
                         for attr in attributes {
                             match attr.name.local_name.as_ref() {
-                                "imagePath" => atlas.tex_filename = attr.value,
-                                _           => {},
+                                "imagePath" => {
+                                    atlas.tex_filename          = attr.value;
+                                    atlas.tex_filename_position = position;
+                                }
+                                _ => {},
                             }
                         }
                     }
                 }
-                Err(event) => {
-                    panic!("parse_tex_atlas() error: {}", event);
+                Ok(XmlEvent::EndDocument) => {
+                    break;
+                }
+                Err(error) => {
+                    // The parser can't recover after a syntax error.
+                    errors.push(format!("{}:{}: {}", xml_filename, error.position(), error.msg()));
+                    break;
                 }
                 _ => {}
             }
         }
 
+        atlas.build_variant_index();
+
         println!("Finished parsing \"{}\".", xml_filename);
         return atlas;
     }
+
+    fn build_variant_index(&mut self) {
+        self.variants.clear();
+        for (index, sub_tex) in self.sub_textures.iter().enumerate() {
+            if !sub_tex.variant.is_empty() {
+                self.variants.entry(sub_tex.variant.clone()).or_insert_with(Vec::new).push(index as i32);
+            }
+        }
+    }
+
+    // Cross-checks the atlas metadata against the image that was actually loaded for it.
+    pub fn validate(&self, xml_filename: &str, image_filename: &str, image_dims: (u32, u32), errors: &mut Vec<String>) {
+        if self.tex_filename != image_filename {
+            errors.push(format!("{}:{}: imagePath \"{}\" doesn't match the atlas image \"{}\".",
+                                xml_filename, self.tex_filename_position, self.tex_filename, image_filename));
+        }
+
+        // Done in i64 so huge values from a broken XML file can't overflow
+        // the sums; they just end up reported as out of bounds. Bad sizes
+        // were already reported by parse_from_xml().
+        let (width, height) = (image_dims.0 as i64, image_dims.1 as i64);
+        for sub_tex in &self.sub_textures {
+            let (x, y) = (sub_tex.x as i64, sub_tex.y as i64);
+            let (w, h) = (sub_tex.width as i64, sub_tex.height as i64);
+            if x < 0 || y < 0 || x + w > width || y + h > height {
+                errors.push(format!("{}:{}: SubTexture '{}' ({},{} {}x{}) is outside the {}x{} atlas image.",
+                                    xml_filename, sub_tex.xml_position, sub_tex.filename, sub_tex.x, sub_tex.y,
+                                    sub_tex.width, sub_tex.height, width, height));
+            }
+        }
+    }
+}
+
+fn parse_int_attr(value: &str, attr_name: &str, location: &str, errors: &mut Vec<String>) -> i32 {
+    // Parsed as a float so values like "12.0" are accepted. NaN, inf and
+    // anything out of i32 range would be silently mangled by the cast.
+    match value.parse::<f64>() {
+        Ok(number) if number.is_finite() && number >= (::std::i32::MIN as f64) && number <= (::std::i32::MAX as f64) => {
+            number as i32
+        }
+        _ => {
+            errors.push(format!("{}: attribute '{}' has invalid numeric value \"{}\".", location, attr_name, value));
+            0
+        }
+    }
 }
 
 // ----------------------------------------------
@@ -134,11 +232,11 @@ pub struct TextureCache {
 }
 
 impl TextureCache {
-    pub fn new<F>(facade: &F, config: &Config) -> TextureCache
+    pub fn new<F>(facade: &F, config: &Config, assets: &AssetLoader) -> TextureCache
                   where F: glium::backend::Facade {
 
         let mut tex_cache = TextureCache{ textures: Vec::new() };
-        tex_cache.load_all_textures(facade, config);
+        tex_cache.load_all_textures(facade, config, assets);
         return tex_cache;
     }
 
@@ -180,47 +278,75 @@ impl TextureCache {
 
         Tile{
             tex_id:   atlas_tex_id,
-            geometry: TileGeometry{ rect: rect, color: color, tex_coords: tex_coords }
+            geometry: TileGeometry{ rect: rect, color: color, tex_coords: tex_coords },
+            tint:     None,
         }
     }
 
-    fn load_all_textures<F>(&mut self, facade: &F, config: &Config)
+    fn load_all_textures<F>(&mut self, facade: &F, config: &Config, assets: &AssetLoader)
                             where F: glium::backend::Facade {
 
         // Preload all the stuff:
-        self.load_atlases(facade, config);
+        self.load_atlases(facade, config, assets);
 
         // Keep it sorted for faster binary searches.
         self.textures.sort_by(|a, b| a.key.cmp(&b.key));
         println!("TextureCache loaded!");
     }
 
-    fn load_atlases<F>(&mut self, facade: &F, config: &Config)
+    fn load_atlases<F>(&mut self, facade: &F, config: &Config, assets: &AssetLoader)
                        where F: glium::backend::Facade {
 
-        let path_sep  = std::path::MAIN_SEPARATOR;
         let base_path = TEXTURE_ATLAS_BASE_PATH;
         let meta_ext  = TEXTURE_ATLAS_META_FILE_EXT;
         let tex_ext   = TEXTURE_ATLAS_TEX_FILE_EXT;
 
+        // Errors are collected for all atlases and reported together at the end.
+        let mut errors = Vec::new();
+
         let tex_atlas_list = config.get_texture_atlases();
         for atlas_file in tex_atlas_list {
-            let tex_file_path = format!("{}{}{}{}", base_path, path_sep, atlas_file, tex_ext);
-            let as_sys_path   = Path::new(&tex_file_path);
+            let tex_file_name  = format!("{}{}", atlas_file, tex_ext);
+            let tex_file_path  = format!("{}/{}", base_path, tex_file_name);
+            let meta_file_path = format!("{}/{}{}", base_path, atlas_file, meta_ext);
+
+            let meta_data = match assets.read_bytes(&meta_file_path) {
+                Some(bytes) => bytes,
+                None        => {
+                    errors.push(format!("{}: can't load texture atlas metadata.", meta_file_path));
+                    continue;
+                }
+            };
+            let atlas = TextureAtlas::parse_from_xml(&meta_data, &meta_file_path, &mut errors);
+
+            if !self.try_load_texture(facade, assets, &tex_file_path, format!("{}", atlas_file), atlas) {
+                errors.push(format!("{}: can't load texture atlas image.", tex_file_path));
+                continue;
+            }
 
-            let meta_file_path = format!("{}{}{}{}", base_path, path_sep, atlas_file, meta_ext);
-            let atlas = TextureAtlas::parse_from_xml(meta_file_path.as_ref());
+            let entry = self.textures.last().unwrap();
+            let dims  = (entry.tex.get_width(), entry.tex.get_height().unwrap());
+            entry.atlas.validate(&meta_file_path, &tex_file_name, dims, &mut errors);
+        }
 
-            if !self.try_load_texture(facade, as_sys_path, format!("{}", atlas_file), atlas) {
-                panic!("Can't load texture atlas \"{}\"!", tex_file_path);
+        if !errors.is_empty() {
+            println!("Texture atlas loading failed with {} error(s):", errors.len());
+            for error in &errors {
+                println!("  {}", error);
             }
+            panic!("Invalid texture atlases! See the error report above.");
         }
     }
 
-    fn try_load_texture<F>(&mut self, facade: &F, file_path: &Path, name_key: String, atlas: TextureAtlas)
-                           -> bool where F: glium::backend::Facade {
+    fn try_load_texture<F>(&mut self, facade: &F, assets: &AssetLoader, file_path: &str, name_key: String,
+                           atlas: TextureAtlas) -> bool where F: glium::backend::Facade {
+
+        let file_data = match assets.read_bytes(file_path) {
+            None       => return false,
+            Some(data) => data,
+        };
 
-        let image = match image::open(file_path) {
+        let image = match image::load_from_memory(&file_data) {
             Err(_)    => return false,
             Ok(image) => image.to_rgba(),
         };
@@ -230,7 +356,7 @@ impl TextureCache {
         let texture = glium::texture::SrgbTexture2d::new(facade, image).unwrap();
 
         println!("Texture '{}' => \"{}\" ({}x{}) successfully loaded.",
-                 name_key, file_path.display(), dims.0, dims.1);
+                 name_key, file_path, dims.0, dims.1);
 
         self.textures.push(TexCacheEntry{ key: name_key, tex: texture, atlas: atlas });
         return true;
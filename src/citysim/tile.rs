@@ -9,7 +9,8 @@
 // See the accompanying LICENSE file for details.
 // ================================================================================================
 
-use citysim::common::{Rect2d, Color};
+use std::f32::consts::PI;
+use citysim::common::{Rect2d, Color, TILE_TINT_PULSE_HZ};
 use citysim::texcache::{TexId, TEX_ID_NONE};
 
 // ----------------------------------------------
@@ -48,6 +49,26 @@ impl TileGeometry {
     }
 }
 
+// ----------------------------------------------
+// TileTint
+// ----------------------------------------------
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TintMode {
+    Flash, // Starts at the tint color and fades linearly back to the base color (e.g. red on damage).
+    Pulse, // Blinks between the tint and base colors, fading out over the duration (e.g. green on upgrade).
+}
+
+// Timed color tint used for feedback effects on a tile.
+#[derive(Copy, Clone)]
+pub struct TileTint {
+    pub mode:       TintMode,
+    pub color:      Color, // Tint color at the start of the effect.
+    pub base_color: Color, // Tile color to restore once the tint expires.
+    pub duration:   f32,   // Total length of the effect in seconds.
+    pub time_left:  f32,   // Seconds remaining.
+}
+
 // ----------------------------------------------
 // Tile
 // ----------------------------------------------
@@ -55,10 +76,144 @@ impl TileGeometry {
 pub struct Tile {
     pub tex_id:   TexId,
     pub geometry: TileGeometry,
+    pub tint:     Option<TileTint>,
 }
 
 impl Tile {
     pub fn new() -> Tile {
-        Tile{ tex_id: TEX_ID_NONE, geometry: TileGeometry::new() }
+        Tile{ tex_id: TEX_ID_NONE, geometry: TileGeometry::new(), tint: None }
+    }
+
+    pub fn is_tinted(&self) -> bool {
+        self.tint.is_some()
+    }
+
+    pub fn apply_tint(&mut self, color: Color, duration: f32) {
+        self.start_tint(TintMode::Flash, color, duration);
+    }
+
+    pub fn apply_pulse(&mut self, color: Color, duration: f32) {
+        self.start_tint(TintMode::Pulse, color, duration);
+    }
+
+    fn start_tint(&mut self, mode: TintMode, color: Color, duration: f32) {
+        if duration <= 0.0 {
+            return;
+        }
+        // Re-tinting a tile that is already tinted keeps the original base color.
+        let base_color = match self.tint {
+            Some(tint) => tint.base_color,
+            None       => self.geometry.color,
+        };
+        self.tint = Some(TileTint{ mode: mode, color: color, base_color: base_color, duration: duration, time_left: duration });
+        self.geometry.color = color;
+    }
+
+    pub fn clear_tint(&mut self) {
+        if let Some(tint) = self.tint {
+            self.geometry.color = tint.base_color;
+            self.tint = None;
+        }
+    }
+
+    // Decays any active tint. Should be called once per frame with the frame delta time.
+    pub fn update_tint(&mut self, delta_secs: f32) {
+        let mut tint = match self.tint {
+            Some(tint) => tint,
+            None       => return,
+        };
+
+        tint.time_left -= delta_secs;
+        if tint.time_left <= 0.0 {
+            self.clear_tint();
+            return;
+        }
+
+        let fade = tint.time_left / tint.duration;
+        let t = match tint.mode {
+            TintMode::Flash => fade,
+            TintMode::Pulse => {
+                let elapsed = tint.duration - tint.time_left;
+                fade * (0.5 + 0.5 * (2.0 * PI * TILE_TINT_PULSE_HZ * elapsed).cos())
+            }
+        };
+        self.geometry.color = Color::lerp(tint.base_color, tint.color, t);
+        self.tint = Some(tint);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Tile;
+    use citysim::common::Color;
+
+    fn assert_color_eq(actual: Color, expected: Color) {
+        let close = |a: f32, b: f32| (a - b).abs() < 0.0001;
+        assert!(close(actual.r, expected.r) && close(actual.g, expected.g) &&
+                close(actual.b, expected.b) && close(actual.a, expected.a),
+                "got ({}, {}, {}, {}), expected ({}, {}, {}, {})",
+                actual.r, actual.g, actual.b, actual.a, expected.r, expected.g, expected.b, expected.a);
+    }
+
+    #[test]
+    fn apply_tint_sets_tint_color() {
+        let mut tile = Tile::new();
+        tile.apply_tint(Color::red(), 1.0);
+        assert!(tile.is_tinted());
+        assert_color_eq(tile.geometry.color, Color::red());
+    }
+
+    #[test]
+    fn apply_tint_ignores_zero_duration() {
+        let mut tile = Tile::new();
+        tile.apply_tint(Color::red(), 0.0);
+        assert!(!tile.is_tinted());
+        assert_color_eq(tile.geometry.color, Color::white());
+    }
+
+    #[test]
+    fn retint_keeps_base_color() {
+        let mut tile = Tile::new();
+        tile.apply_tint(Color::red(), 1.0);
+        tile.update_tint(0.5);
+        tile.apply_tint(Color::blue(), 1.0);
+        assert_color_eq(tile.tint.unwrap().base_color, Color::white());
+        tile.update_tint(2.0);
+        assert_color_eq(tile.geometry.color, Color::white());
+    }
+
+    #[test]
+    fn flash_decays_linearly() {
+        let mut tile = Tile::new();
+        tile.apply_tint(Color::black(), 2.0);
+        tile.update_tint(0.5);
+        assert_color_eq(tile.geometry.color, Color{ r: 0.25, g: 0.25, b: 0.25, a: 1.0 });
+        tile.update_tint(1.0);
+        assert_color_eq(tile.geometry.color, Color{ r: 0.75, g: 0.75, b: 0.75, a: 1.0 });
+    }
+
+    #[test]
+    fn tint_expires_and_restores_base_color() {
+        let mut tile = Tile::new();
+        tile.apply_tint(Color::red(), 1.0);
+        tile.update_tint(1.0);
+        assert!(!tile.is_tinted());
+        assert_color_eq(tile.geometry.color, Color::white());
+    }
+
+    #[test]
+    fn pulse_returns_to_base_between_blinks() {
+        let mut tile = Tile::new();
+        tile.apply_pulse(Color::black(), 4.0);
+        assert_color_eq(tile.geometry.color, Color::black());
+        // Half a period in (pulse rate is 2Hz), the pulse is back at the base color.
+        tile.update_tint(0.25);
+        assert_color_eq(tile.geometry.color, Color::white());
+        // A full period in, it's at the tint color again, scaled down by the fade.
+        tile.update_tint(0.25);
+        assert_color_eq(tile.geometry.color, Color{ r: 0.125, g: 0.125, b: 0.125, a: 1.0 });
+        tile.update_tint(3.5);
+        assert!(!tile.is_tinted());
+        assert_color_eq(tile.geometry.color, Color::white());
     }
 }
@@ -20,11 +20,15 @@ extern crate image;
 extern crate xml;
 
 mod citysim;
+use citysim::assets::*;
+use citysim::clock::*;
 use citysim::common::*;
+use citysim::input::*;
 use citysim::render::*;
 use citysim::texcache::*;
 
-use glium::{DisplayBuild, Surface};
+use glium::DisplayBuild;
+use glium::glutin::{MouseButton, VirtualKeyCode};
 
 fn main() {
     let config = Config::new();
@@ -35,7 +39,8 @@ fn main() {
         .build_glium()
         .unwrap();
 
-    let tex_cache = TextureCache::new(&display, &config);
+    let assets    = AssetLoader::new();
+    let tex_cache = TextureCache::new(&display, &config, &assets);
     let mut batch = BatchRenderer::new(&display, &config, &tex_cache);
 
     let tiles_x = 4;
@@ -43,7 +48,10 @@ fn main() {
     let tile_width  = 256*2;
     let tile_height = 180*2;
 
-    let mut tex_id = 0;
+    let variant_seed = config.get_tile_variant_seed();
+    let house_atlas  = &tex_cache.get_tex_from_id(0).unwrap().atlas;
+
+    let mut tiles = Vec::new();
     let mut x_offset: i32;
     let mut y_offset: i32;
 
@@ -61,34 +69,61 @@ fn main() {
         for x in 0..tiles_x {
             let tx = (x * tile_width)  + x_offset;
             let ty = (y * tile_height) - y_offset;
-            let tile = tex_cache.tile_from_atlas(0, tex_id, Point2d::with_coords(tx, ty), Color::white(), 2);
-            batch.add_tile(&tile);
+            let tex_id = house_atlas.pick_variant("house", Point2d::with_coords(x, y), variant_seed).unwrap();
+            tiles.push(tex_cache.tile_from_atlas(0, tex_id, Point2d::with_coords(tx, ty), Color::white(), 2));
         }
-
-        tex_id = (tex_id + 1) % 4;
     }
 
-    batch.update();
+    let mut input = InputState::new();
+    let mut clock = FrameClock::new();
 
     loop {
-        let mut target = display.draw();
+        clock.begin_frame();
 
-        target.clear_color(0.1, 0.1, 0.1, 1.0);
+        // Tints change the tile colors, so the batch is rebuilt every frame.
+        batch.clear();
+        for tile in &mut tiles {
+            tile.update_tint(clock.get_delta_time());
+            batch.add_tile(tile, RenderLayer::Objects);
+        }
+        batch.update();
 
-        //batch.update();
-        batch.draw(&mut target, &tex_cache);
-        //batch.clear();
+        let mut target = display.draw();
+        batch.draw(&display, &mut target, &tex_cache);
 
         target.finish().unwrap();
 
         assert_no_gl_error!(display);
 
+        input.begin_frame();
         for ev in display.poll_events() {
+            input.handle_event(&ev);
             match ev {
                 glium::glutin::Event::Closed => return,
                 _ => ()
             }
         }
+
+        // Feedback effects demo: left click flashes the tile under the cursor red,
+        // right click pulses it green. Later tiles are drawn on top, so search backwards.
+        let cursor = input.get_cursor_pos();
+        if let Some(tile) = tiles.iter_mut().rev().find(|tile| tile.geometry.rect.contains(cursor)) {
+            if input.was_button_pressed(MouseButton::Left) {
+                tile.apply_tint(Color::red(), 0.5);
+            } else if input.was_button_pressed(MouseButton::Right) {
+                tile.apply_pulse(Color::gree(), 2.0);
+            }
+        }
+
+        // Background demo: G toggles between the default background and a gradient.
+        if input.was_pressed(VirtualKeyCode::G) {
+            let background = match batch.get_background() {
+                Background::Solid(_) => Background::VerticalGradient(Color{ r: 0.2, g: 0.3, b: 0.5, a: 1.0 },
+                                                                      Color{ r: 0.1, g: 0.1, b: 0.1, a: 1.0 }),
+                Background::VerticalGradient(..) => config.get_default_background(),
+            };
+            batch.set_background(background);
+        }
     }
 }
 